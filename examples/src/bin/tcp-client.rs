@@ -1,6 +1,5 @@
 use ptls::Ptls;
-use rand::thread_rng;
-use rsa::{pkcs1::DecodeRsaPublicKey, RsaPrivateKey, RsaPublicKey};
+use rsa::{pkcs1::DecodeRsaPublicKey, RsaPublicKey};
 use std::time::Duration;
 
 #[tokio::main]
@@ -9,14 +8,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let server_public = RsaPublicKey::read_pkcs1_pem_file("./certs/public.pem")
         .expect("Cannot read public key");
 
-    // Generate a new private key for the client
-    let private_key = RsaPrivateKey::new(&mut thread_rng(), 512)?;
-
     // Connect to the server
     let mut client = tokio::net::TcpStream::connect("localhost:7811").await?;
 
-    // Upgrade the TCP connection to a pTLS-encrypted tunnel
-    let mut client_ptls = Ptls::new(client.split(), private_key);
+    // Upgrade the TCP connection to a pTLS-encrypted tunnel, using a freshly
+    // generated private key for the client
+    let mut client_ptls = Ptls::ephemeral(client.split(), 512).await?;
     client_ptls.set_public_key(server_public);
     client_ptls.send_public_key().await?;
 
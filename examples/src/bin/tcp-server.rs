@@ -1,12 +1,15 @@
 use ptls::Ptls;
 use rsa::{pkcs1::DecodeRsaPrivateKey, RsaPrivateKey};
+use std::sync::Arc;
 use tokio::net::TcpListener;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Load server private key
-    let server_private = RsaPrivateKey::read_pkcs1_pem_file("./certs/private.pem")
-        .expect("Cannot read private key");
+    // Load server private key once and share it with every connection
+    let server_private = Arc::new(
+        RsaPrivateKey::read_pkcs1_pem_file("./certs/private.pem")
+            .expect("Cannot read private key"),
+    );
 
     // Start TCP listener
     let listener = TcpListener::bind("localhost:7811").await?;
@@ -18,7 +21,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             println!("Accepted connection from {addr}");
 
             // Spawn a task to handle the connection
-            tokio::spawn(handle_connection(peer, server_private.clone()));
+            tokio::spawn(handle_connection(peer, Arc::clone(&server_private)));
         } else {
             eprintln!("Failed to accept connection");
         }
@@ -27,7 +30,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 async fn handle_connection(
     mut peer: tokio::net::TcpStream,
-    server_private: RsaPrivateKey,
+    server_private: Arc<RsaPrivateKey>,
 ) {
     let mut server_ptls = Ptls::new(peer.split(), server_private);
 
@@ -0,0 +1,25 @@
+/// Cryptographic settings that trade performance for side-channel
+/// resistance.
+///
+/// The default favors throughput. Security-sensitive deployments should
+/// start from [`CryptoConfig::hardened`].
+#[derive(Debug, Clone, Default)]
+pub struct CryptoConfig {
+    /// Blind RSA private-key operations with a random factor, hiding the
+    /// timing of the modular exponentiation from the peer.
+    pub rsa_blinding: bool,
+    /// Decrypt every block of a payload even after one of them has failed,
+    /// so that the position of a corrupted block cannot be inferred from
+    /// the time it took to reject the payload.
+    pub constant_time: bool,
+}
+
+impl CryptoConfig {
+    /// Enables every side-channel countermeasure.
+    pub fn hardened() -> Self {
+        Self {
+            rsa_blinding: true,
+            constant_time: true,
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use super::Error;
+use arc_swap::ArcSwap;
+use rsa::RsaPrivateKey;
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::task::JoinHandle;
+
+/// A server private key that can be replaced while the server is running.
+///
+/// Tunnels take a snapshot of the key when they are created, so installing a
+/// renewed key with [`swap_private_key`](Self::swap_private_key) leaves the
+/// existing connections on the old key while new handshakes use the new one.
+/// Clones share the same key.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    current: Arc<ArcSwap<Current>>,
+}
+
+#[derive(Debug)]
+struct Current {
+    private_key: Arc<RsaPrivateKey>,
+    expires_at: Option<SystemTime>,
+}
+
+/// Audit events emitted by the task spawned with
+/// [`Credentials::spawn_renewal`].
+#[derive(Debug)]
+pub enum RenewalEvent {
+    /// The renewal callback is invoked for credentials expiring at the given
+    /// time.
+    Started(SystemTime),
+    /// Renewed credentials expiring at the given time have been installed.
+    Renewed(SystemTime),
+    /// The renewal callback failed, it is retried after the given delay.
+    Failed(Error, Duration),
+}
+
+impl Credentials {
+    /// Creates credentials serving `private_key`.
+    pub fn new(private_key: impl Into<Arc<RsaPrivateKey>>) -> Self {
+        Self {
+            current: Arc::new(ArcSwap::from_pointee(Current {
+                private_key: private_key.into(),
+                expires_at: None,
+            })),
+        }
+    }
+
+    /// Sets the time the current key expires at.
+    pub fn expiring_at(self, expires_at: SystemTime) -> Self {
+        self.renew(self.load(), expires_at);
+        self
+    }
+
+    /// Returns the key new tunnels should be created with.
+    pub fn load(&self) -> Arc<RsaPrivateKey> {
+        Arc::clone(&self.current.load().private_key)
+    }
+
+    /// Returns the time the current key expires at, if it expires.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.current.load().expires_at
+    }
+
+    /// Atomically installs a renewed key that does not expire, returning the
+    /// previous one.
+    pub fn swap_private_key(
+        &self,
+        private_key: impl Into<Arc<RsaPrivateKey>>,
+    ) -> Arc<RsaPrivateKey> {
+        self.install(private_key.into(), None)
+    }
+
+    /// Atomically installs a renewed key expiring at `expires_at`, returning
+    /// the previous one.
+    pub fn renew(
+        &self,
+        private_key: impl Into<Arc<RsaPrivateKey>>,
+        expires_at: SystemTime,
+    ) -> Arc<RsaPrivateKey> {
+        self.install(private_key.into(), Some(expires_at))
+    }
+
+    fn install(
+        &self,
+        private_key: Arc<RsaPrivateKey>,
+        expires_at: Option<SystemTime>,
+    ) -> Arc<RsaPrivateKey> {
+        let previous = self.current.swap(Arc::new(Current {
+            private_key,
+            expires_at,
+        }));
+
+        Arc::clone(&previous.private_key)
+    }
+
+    /// Spawns a task invoking `renew` `renew_before` the credentials expire
+    /// and installing the key it returns. Failed renewals are retried until
+    /// one succeeds, every step is reported to `on_event`.
+    ///
+    /// The task stops once the credentials no longer expire.
+    pub fn spawn_renewal<F, Fut, E>(
+        &self,
+        renew_before: Duration,
+        mut renew: F,
+        on_event: E,
+    ) -> JoinHandle<()>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(RsaPrivateKey, SystemTime), Error>> + Send,
+        E: Fn(RenewalEvent) + Send + 'static,
+    {
+        let credentials = self.clone();
+        let retry_in = (renew_before / 4).max(Duration::from_secs(1));
+
+        tokio::spawn(async move {
+            while let Some(expires_at) = credentials.expires_at() {
+                let renew_at = expires_at.checked_sub(renew_before).unwrap_or(expires_at);
+                if let Ok(wait) = renew_at.duration_since(SystemTime::now()) {
+                    tokio::time::sleep(wait).await;
+                }
+
+                on_event(RenewalEvent::Started(expires_at));
+
+                match renew().await {
+                    Ok((private_key, expires_at)) => {
+                        credentials.renew(private_key, expires_at);
+                        on_event(RenewalEvent::Renewed(expires_at));
+                    }
+                    Err(e) => {
+                        on_event(RenewalEvent::Failed(e, retry_in));
+                        tokio::time::sleep(retry_in).await;
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl From<&Credentials> for Arc<RsaPrivateKey> {
+    fn from(credentials: &Credentials) -> Self {
+        credentials.load()
+    }
+}
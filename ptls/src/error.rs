@@ -1,5 +1,5 @@
 use super::payload::Error as PayloadError;
-use rsa::pkcs1::Error as Pkcs1Error;
+use rsa::{pkcs1::Error as Pkcs1Error, Error as RsaError};
 use std::{
     error::Error as StdError,
     fmt::{self, Display, Formatter},
@@ -10,6 +10,10 @@ use std::{
 pub enum Error {
     /// pkcs1-related errors
     Pkcs1(Pkcs1Error),
+    /// RSA key errors
+    Rsa(RsaError),
+    /// The number of primes is not supported for the key size.
+    PrimeCount(usize),
     /// The client is not yet ready to receive data.
     NotReady,
     /// The connection has been lost due to an error during transmission.
@@ -18,13 +22,28 @@ pub enum Error {
     Timeout,
     /// payload-related errors
     Payload(PayloadError),
+    /// A stream chunk arrived out of order, carrying the given index.
+    ChunkOutOfOrder(u64),
+    /// The peer requested a server name with no registered identity.
+    UnknownServerName(String),
+    /// The tunnel has been closed.
+    Closed,
+    /// The handshake was rejected by the hello inspector.
+    Rejected,
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         match self {
             Self::Pkcs1(error) => error.fmt(f),
+            Self::Rsa(error) => error.fmt(f),
             Self::Payload(error) => error.fmt(f),
+            Self::PrimeCount(primes) => {
+                write!(
+                    f,
+                    "{primes} primes are not supported for this RSA key size."
+                )
+            }
             Self::NotReady => {
                 f.write_str("Public key not received yet. Consider awaiting the `handshake`.")
             }
@@ -32,10 +51,18 @@ impl Display for Error {
                 f.write_str("Transmission interrupted due to an error. Consider reconnecting.")
             }
             Self::Timeout => f.write_str("Key exchange timed out. Please try reconnecting."),
+            Self::ChunkOutOfOrder(index) => {
+                write!(f, "Stream chunk {index} arrived out of order.")
+            }
+            Self::Closed => f.write_str("The tunnel has been closed."),
+            Self::Rejected => f.write_str("The handshake has been rejected."),
+            Self::UnknownServerName(server_name) => {
+                write!(f, "No identity registered for server name {server_name:?}.")
+            }
         }
     }
 }
 
 impl StdError for Error {}
 
-error_impl_from!(Pkcs1, Payload);
+error_impl_from!(Pkcs1, Rsa, Payload);
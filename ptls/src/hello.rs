@@ -0,0 +1,48 @@
+use rsa::RsaPrivateKey;
+use std::{fmt, sync::Arc};
+
+/// The opening of a client handshake, as seen by the server before it
+/// performs any RSA operation. None of its fields are authenticated.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ClientHello {
+    /// Server name requested by the client.
+    pub server_name: Option<String>,
+    /// Protocol version announced in the public key payload header.
+    pub version: u16,
+}
+
+/// Decision taken by a [`HelloInspector`].
+#[derive(Debug, Clone)]
+pub enum HelloDecision {
+    /// Continue the handshake with the identity selected by server name.
+    Accept,
+    /// Continue the handshake, serving the given private key.
+    AcceptWith(Arc<RsaPrivateKey>),
+    /// Abort the handshake.
+    Reject,
+}
+
+/// Server callback inspecting the [`ClientHello`] before any RSA work, to
+/// route tenants or block obsolete clients cheaply.
+///
+/// It is implemented for closures taking a `&ClientHello`.
+pub trait HelloInspector: Send + Sync {
+    /// Decides how the handshake described by `hello` continues.
+    fn inspect(&self, hello: &ClientHello) -> HelloDecision;
+}
+
+impl<F> HelloInspector for F
+where
+    F: Fn(&ClientHello) -> HelloDecision + Send + Sync,
+{
+    fn inspect(&self, hello: &ClientHello) -> HelloDecision {
+        self(hello)
+    }
+}
+
+impl fmt::Debug for dyn HelloInspector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HelloInspector")
+    }
+}
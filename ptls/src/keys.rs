@@ -0,0 +1,81 @@
+use super::Error;
+use num_bigint_dig::RandPrime;
+use rand::thread_rng;
+use rsa::{
+    traits::{PrivateKeyParts, PublicKeyParts},
+    BigUint, RsaPrivateKey,
+};
+
+/// Largest number of primes accepted for any key size.
+pub const MAX_PRIMES: usize = 4;
+
+/// Returns the maximum number of primes that keeps a `bits`-sized modulus
+/// as hard to factor as a two-prime one.
+pub fn max_primes(bits: usize) -> usize {
+    match bits {
+        0..=1023 => 2,
+        1024..=4095 => 3,
+        _ => MAX_PRIMES,
+    }
+}
+
+/// Generates a multi-prime RSA private key.
+///
+/// Private-key operations on the resulting key are faster than on a
+/// two-prime key of the same size, while its public half is an ordinary
+/// `(n, e)` pair, so peers never need to know the difference.
+pub fn generate_multi_prime(bits: usize, primes: usize) -> Result<RsaPrivateKey, Error> {
+    if primes < 2 || primes > max_primes(bits) {
+        return Err(Error::PrimeCount(primes));
+    }
+
+    let mut rng = thread_rng();
+    let exp = BigUint::from(65537u32);
+
+    loop {
+        let mut todo = bits;
+        let mut factors = Vec::with_capacity(primes);
+
+        for i in 0..primes {
+            let prime: BigUint = rng.gen_prime(todo / (primes - i));
+            todo -= prime.bits();
+            factors.push(prime);
+        }
+
+        let n = factors.iter().product::<BigUint>();
+        if n.bits() != bits {
+            continue;
+        }
+
+        match RsaPrivateKey::from_primes(factors, exp.clone()) {
+            Ok(key) => return Ok(key),
+            // Equal primes or a non-invertible exponent, retry.
+            Err(_) => continue,
+        }
+    }
+}
+
+/// Generates a fresh key on the blocking thread pool, keeping the async
+/// runtime responsive while the primes are searched for.
+pub async fn generate_ephemeral(bits: usize) -> Result<RsaPrivateKey, Error> {
+    let generated =
+        tokio::task::spawn_blocking(move || RsaPrivateKey::new(&mut thread_rng(), bits)).await;
+
+    match generated {
+        Ok(private_key) => Ok(private_key?),
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Checks the consistency of a private key and that it does not use more
+/// primes than its size allows.
+pub fn validate(private_key: &RsaPrivateKey) -> Result<(), Error> {
+    private_key.validate()?;
+
+    let primes = private_key.primes().len();
+    if primes > max_primes(private_key.n().bits()) {
+        return Err(Error::PrimeCount(primes));
+    }
+
+    Ok(())
+}
@@ -31,22 +31,46 @@
 #[macro_use]
 mod macros;
 
+mod config;
+mod credentials;
 mod error;
+mod hello;
+mod listener;
+mod policy;
+mod router;
+
+/// RSA key helpers
+pub mod keys;
 
 /// mTLS payload
 pub mod payload;
 
+mod stream;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
 #[cfg(test)]
 mod tests;
 
+pub use config::CryptoConfig;
+pub use credentials::{Credentials, RenewalEvent};
 pub use error::Error;
-use payload::{PtlsPayload, PtlsPayloadType};
+pub use hello::{ClientHello, HelloDecision, HelloInspector};
+pub use listener::{Connection, ListenerConfig, PtlsListener, ShutdownReport, TcpPtls};
+use payload::{PtlsPayload, PtlsPayloadHeader, PtlsPayloadType};
+pub use policy::{Admission, HandshakePolicy, TokenBucket};
+pub use router::Router;
 
 use rsa::{
     pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey},
     RsaPrivateKey, RsaPublicKey,
 };
-use std::{sync::Mutex as StdMutex, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     sync::Mutex,
@@ -58,10 +82,14 @@ use tokio::{
 pub struct Ptls<R, W> {
     read: Mutex<R>,
     write: Mutex<W>,
-    private_key: RsaPrivateKey,
-    public_key: Option<RsaPublicKey>,
+    private_key: Arc<RsaPrivateKey>,
+    public_key: Option<Arc<RsaPublicKey>>,
     state: StdMutex<PtlsState>,
     timeout: Option<Duration>,
+    crypto: CryptoConfig,
+    identities: HashMap<String, Arc<RsaPrivateKey>>,
+    server_name: Option<String>,
+    hello_inspector: Option<Arc<dyn HelloInspector>>,
 }
 
 /// pTLS state
@@ -73,6 +101,9 @@ pub enum PtlsState {
     Authenticated,
     /// An error occurred during transmission.
     TransmitError,
+    /// The tunnel has been closed with [`Ptls::close`], nothing can be sent
+    /// anymore.
+    Closed,
 }
 
 impl<R, W> Ptls<R, W>
@@ -83,17 +114,32 @@ where
     /// Creates a new pTLS tunnel. The `public_key`, which should be acquired
     /// from the peer, is optional until messages are sent. It can be obtained
     /// using the `handshake` or `set_public_key` functions.
-    pub fn new((read, write): (R, W), private_key: RsaPrivateKey) -> Self {
+    ///
+    /// Keys are held behind an [`Arc`], so a server can parse its private key
+    /// once (including the RSA precomputations) and share it across every
+    /// tunnel it accepts instead of cloning it per connection.
+    pub fn new((read, write): (R, W), private_key: impl Into<Arc<RsaPrivateKey>>) -> Self {
         Self {
             read: Mutex::new(read),
             write: Mutex::new(write),
             public_key: None,
-            private_key,
+            private_key: private_key.into(),
             state: StdMutex::new(PtlsState::AwaitingPublicKey),
             timeout: None,
+            crypto: CryptoConfig::default(),
+            identities: HashMap::new(),
+            server_name: None,
+            hello_inspector: None,
         }
     }
 
+    /// Creates a new pTLS tunnel with a freshly generated `bits`-sized
+    /// private key, for clients that do not need a long-term identity.
+    pub async fn ephemeral((read, write): (R, W), bits: usize) -> Result<Self, Error> {
+        let private_key = keys::generate_ephemeral(bits).await?;
+        Ok(Self::new((read, write), private_key))
+    }
+
     /// Consumes the `Ptls`, returning the wrapped read and writer.
     pub fn into_inner(self) -> (R, W) {
         (self.read.into_inner(), self.write.into_inner())
@@ -104,10 +150,46 @@ where
         self.timeout = timeout
     }
 
+    /// Sets the side-channel countermeasures applied to received payloads.
+    pub fn set_crypto_config(&mut self, config: CryptoConfig) {
+        self.crypto = config
+    }
+
+    /// Registers an additional identity served under `server_name`. Clients
+    /// requesting that name with [`set_server_name`](Self::set_server_name)
+    /// are served with `private_key` instead of the one given to
+    /// [`new`](Self::new).
+    pub fn add_identity(
+        &mut self,
+        server_name: impl Into<String>,
+        private_key: impl Into<Arc<RsaPrivateKey>>,
+    ) {
+        self.identities
+            .insert(server_name.into(), private_key.into());
+    }
+
+    /// Requests the identity registered under `server_name` from the peer.
+    /// The name is transmitted in clear before the public key.
+    pub fn set_server_name(&mut self, server_name: impl Into<String>) {
+        self.server_name = Some(server_name.into())
+    }
+
+    /// Sets the callback inspecting the client's opening messages during
+    /// [`handshake`](Self::handshake), before any RSA operation.
+    pub fn set_hello_inspector(&mut self, inspector: Arc<dyn HelloInspector>) {
+        self.hello_inspector = Some(inspector)
+    }
+
+    /// Returns the requested server name, on the server side the name of the
+    /// identity that served the connection.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
     /// Sets the `public_key`, typically used for hard-coded keys. To ensure
     /// security, at least one of the two public keys must be hard-coded.
-    pub fn set_public_key(&mut self, public_key: RsaPublicKey) {
-        self.public_key = Some(public_key);
+    pub fn set_public_key(&mut self, public_key: impl Into<Arc<RsaPublicKey>>) {
+        self.public_key = Some(public_key.into());
         self.set_state(PtlsState::Authenticated)
     }
 
@@ -115,67 +197,111 @@ where
         *self.state.lock().unwrap() = state
     }
 
+    /// Marks the tunnel as broken and passes `error` through.
+    fn fail(&self, error: Error) -> Error {
+        self.set_state(PtlsState::TransmitError);
+        error
+    }
+
     /// Returns the current state of the pTLS connection.
     pub fn get_state(&self) -> PtlsState {
         (*self.state.lock().unwrap()).clone()
     }
 
-    /// Retrieves the `public_key` from the peer.  
+    /// Retrieves the `public_key` from the peer.
     pub async fn handshake(&mut self) -> Result<(), Error> {
-        let payload;
-
-        match self.timeout {
-            Some(duration) => {
-                tokio::select! {
-                    received_cert = self.receive_inner() => {
-                        payload = received_cert;
-                    },
-                    _ = tokio::time::sleep(duration) => {
-                        self.set_state(PtlsState::TransmitError);
-                        return Err(Error::Timeout);
-                    }
-                }
-            }
-            None => {
-                payload = self.receive_inner().await;
-            },
+        let received = match self.timeout {
+            Some(duration) => tokio::time::timeout(duration, self.receive_public_key())
+                .await
+                .unwrap_or(Err(Error::Timeout)),
+            None => self.receive_public_key().await,
         };
 
-        match payload {
-            Ok(payload) => {
-                match payload.content_type {
-                    PtlsPayloadType::PublicKey => {
-                        match RsaPublicKey::from_pkcs1_der(&payload.payload) {
-                            Ok(cert) => {
-                                self.set_public_key(cert);
-                                Ok(())
-                            }
-                            Err(e) => {
-                                self.set_state(PtlsState::TransmitError);
-                                Err(Error::Pkcs1(e))
-                            }
-                        }
-                    }
-                    _ => {
-                        self.set_state(PtlsState::TransmitError);
-                        Err(Error::Payload(payload::Error::InvalidContentType))
+        received.map_err(|e| self.fail(e))
+    }
+
+    async fn receive_public_key(&mut self) -> Result<(), Error> {
+        if let PtlsState::TransmitError = self.get_state() {
+            return Err(Error::SocketDied);
+        }
+
+        let mut read = self.read.lock().await;
+
+        loop {
+            let header = PtlsPayloadHeader::read(&mut *read).await?;
+
+            match header.content_type {
+                PtlsPayloadType::ServerName if self.server_name.is_none() => {
+                    let payload = PtlsPayload::collect_body(
+                        header,
+                        &mut *read,
+                        &self.private_key,
+                        &self.crypto,
+                    )
+                    .await?;
+
+                    self.server_name = Some(
+                        String::from_utf8(payload.payload)
+                            .map_err(|e| Error::UnknownServerName(e.to_string()))?,
+                    );
+                }
+                PtlsPayloadType::PublicKey => {
+                    let decision = match &self.hello_inspector {
+                        Some(inspector) => inspector.inspect(&ClientHello {
+                            server_name: self.server_name.clone(),
+                            version: header.version,
+                        }),
+                        None => HelloDecision::Accept,
+                    };
+
+                    match decision {
+                        HelloDecision::Accept => self.private_key = self.requested_identity()?,
+                        HelloDecision::AcceptWith(private_key) => self.private_key = private_key,
+                        HelloDecision::Reject => return Err(Error::Rejected),
                     }
+
+                    let payload = PtlsPayload::collect_body(
+                        header,
+                        &mut *read,
+                        &self.private_key,
+                        &self.crypto,
+                    )
+                    .await?;
+                    let public_key = RsaPublicKey::from_pkcs1_der(&payload.payload)?;
+
+                    drop(read);
+                    self.set_public_key(public_key);
+
+                    return Ok(());
                 }
+                _ => return Err(Error::Payload(payload::Error::InvalidContentType)),
             }
-            Err(e) => {
-                self.set_state(PtlsState::TransmitError);
-                Err(e)
-            }
         }
     }
 
-    /// Sends the `public_key` to the peer for key exchange.
+    /// Returns the identity registered under the requested server name.
+    fn requested_identity(&self) -> Result<Arc<RsaPrivateKey>, Error> {
+        match &self.server_name {
+            Some(server_name) => match self.identities.get(server_name) {
+                Some(private_key) => Ok(Arc::clone(private_key)),
+                None => Err(Error::UnknownServerName(server_name.clone())),
+            },
+            None => Ok(Arc::clone(&self.private_key)),
+        }
+    }
+
+    /// Sends the `public_key` to the peer for key exchange, preceded by the
+    /// requested server name if there is one.
     pub async fn send_public_key(&mut self) -> Result<(), Error> {
-        match RsaPublicKey::from(&self.private_key).to_pkcs1_der() {
+        if let Some(server_name) = &self.server_name {
+            self.send_inner(server_name.as_bytes(), PtlsPayloadType::ServerName)
+                .await?;
+        }
+
+        match self.private_key.to_public_key().to_pkcs1_der() {
             Ok(cert) => {
                 let cert = cert.as_bytes();
-                self.send_inner(cert, PtlsPayloadType::PublicKey).await.unwrap();
-                Ok(())
+                self.send_inner(cert, PtlsPayloadType::PublicKey).await
             }
             Err(e) => {
                 self.set_state(PtlsState::TransmitError);
@@ -186,7 +312,8 @@ where
 
     /// Encrypts the data and transmits it to the peer.
     pub async fn send(&self, data: &[u8]) -> Result<(), Error> {
-        self.send_inner(data, PtlsPayloadType::EncryptedTraffic).await
+        self.send_inner(data, PtlsPayloadType::EncryptedTraffic)
+            .await
     }
 
     async fn send_inner(&self, data: &[u8], content_type: PtlsPayloadType) -> Result<(), Error> {
@@ -211,9 +338,18 @@ where
             }
             PtlsState::AwaitingPublicKey => Err(Error::NotReady),
             PtlsState::TransmitError => Err(Error::SocketDied),
+            PtlsState::Closed => Err(Error::Closed),
         }
     }
 
+    /// Notifies the peer that no more data will be sent and closes the
+    /// sending side of the tunnel.
+    pub async fn close(&self) -> Result<(), Error> {
+        self.send_inner(&[], PtlsPayloadType::CloseNotify).await?;
+        self.set_state(PtlsState::Closed);
+        Ok(())
+    }
+
     /// Receives and decrypts data from the peer.
     pub async fn receive(&self) -> Result<Vec<u8>, Error> {
         let received = self.receive_inner().await?;
@@ -232,7 +368,8 @@ where
             _ => {
                 let received = async {
                     let stream = &mut *(self.read.lock().await);
-                    let payload = PtlsPayload::collect_once(stream, &self.private_key).await?;
+                    let payload =
+                        PtlsPayload::collect_once(stream, &self.private_key, &self.crypto).await?;
                     Ok(payload)
                 }
                 .await;
@@ -0,0 +1,359 @@
+use super::{Admission, Credentials, Error, HandshakePolicy, HelloInspector, Ptls};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, ToSocketAddrs,
+    },
+    sync::{mpsc, watch, Mutex, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+
+/// A pTLS tunnel over a TCP connection.
+pub type TcpPtls = Ptls<OwnedReadHalf, OwnedWriteHalf>;
+
+/// Limits applied by a [`PtlsListener`].
+#[derive(Clone)]
+pub struct ListenerConfig {
+    /// Maximum number of handshakes in progress at once.
+    pub max_handshakes: usize,
+    /// Maximum number of established connections alive at once. Once
+    /// reached, the listener stops accepting until a [`Connection`] is
+    /// dropped.
+    pub max_connections: usize,
+    /// Time a peer is given to complete the handshake.
+    pub handshake_timeout: Option<Duration>,
+    /// Policy deciding whether a handshake with a peer may start, see
+    /// [`TokenBucket`](crate::TokenBucket) for per-address throttling.
+    pub policy: Option<Arc<dyn HandshakePolicy>>,
+    /// Callback inspecting the client's opening messages before any RSA
+    /// work.
+    pub hello_inspector: Option<Arc<dyn HelloInspector>>,
+    /// Additional identities, served to the clients requesting their server
+    /// name.
+    pub identities: HashMap<String, Credentials>,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            max_handshakes: 128,
+            max_connections: 10_000,
+            handshake_timeout: Some(Duration::from_secs(10)),
+            policy: None,
+            hello_inspector: None,
+            identities: HashMap::new(),
+        }
+    }
+}
+
+impl fmt::Debug for ListenerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ListenerConfig")
+            .field("max_handshakes", &self.max_handshakes)
+            .field("max_connections", &self.max_connections)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("policy", &self.policy.is_some())
+            .field("hello_inspector", &self.hello_inspector)
+            .field("identities", &self.identities.keys())
+            .finish()
+    }
+}
+
+/// Connections drained and aborted by [`PtlsListener::shutdown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Connections dropped within the grace period.
+    pub drained: usize,
+    /// Connections still alive after the grace period, which have been
+    /// terminated.
+    pub aborted: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lifecycle {
+    Running,
+    Draining,
+    Terminated,
+}
+
+/// An established connection accepted by a [`PtlsListener`]. Dropping it
+/// frees its connection slot.
+#[derive(Debug)]
+pub struct Connection {
+    tunnel: TcpPtls,
+    peer_addr: SocketAddr,
+    lifecycle: watch::Receiver<Lifecycle>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Connection {
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Receives and decrypts data from the peer.
+    ///
+    /// Once the listener is shutting down, an idle connection waiting here
+    /// is closed with a close_notify and [`Error::Closed`] is returned.
+    pub async fn receive(&self) -> Result<Vec<u8>, Error> {
+        tokio::select! {
+            received = self.tunnel.receive() => received,
+            lifecycle = self.left(Lifecycle::Running) => match lifecycle {
+                Lifecycle::Terminated => Err(self.tunnel.fail(Error::SocketDied)),
+                _ => {
+                    self.tunnel.close().await?;
+                    Err(Error::Closed)
+                }
+            },
+        }
+    }
+
+    /// Encrypts the data and transmits it to the peer, unless the listener
+    /// has terminated the connection.
+    pub async fn send(&self, data: &[u8]) -> Result<(), Error> {
+        tokio::select! {
+            biased;
+            _ = self.left(Lifecycle::Draining) => Err(self.tunnel.fail(Error::SocketDied)),
+            sent = self.tunnel.send(data) => sent,
+        }
+    }
+
+    /// Resolves once the listener has moved past `lifecycle`.
+    async fn left(&self, lifecycle: Lifecycle) -> Lifecycle {
+        let mut receiver = self.lifecycle.clone();
+        let current = receiver
+            .wait_for(|current| *current != lifecycle && *current != Lifecycle::Running)
+            .await
+            .map(|current| *current);
+
+        match current {
+            Ok(current) => current,
+            // The listener is gone, it will not shut the connection down.
+            Err(_) => std::future::pending().await,
+        }
+    }
+}
+
+impl Deref for Connection {
+    type Target = TcpPtls;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tunnel
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tunnel
+    }
+}
+
+/// A TCP listener performing pTLS handshakes in the background and handing
+/// out established connections.
+#[derive(Debug)]
+pub struct PtlsListener {
+    incoming: Mutex<mpsc::Receiver<Connection>>,
+    local_addr: SocketAddr,
+    task: StdMutex<Option<JoinHandle<()>>>,
+    connections: Arc<Semaphore>,
+    max_connections: usize,
+    lifecycle: watch::Sender<Lifecycle>,
+}
+
+impl PtlsListener {
+    /// Binds a listener serving `credentials` to `addr`.
+    pub async fn bind(
+        addr: impl ToSocketAddrs,
+        credentials: Credentials,
+        config: ListenerConfig,
+    ) -> io::Result<Self> {
+        Self::from_tcp(TcpListener::bind(addr).await?, credentials, config)
+    }
+
+    /// Serves `credentials` on an already bound TCP listener.
+    pub fn from_tcp(
+        listener: TcpListener,
+        credentials: Credentials,
+        config: ListenerConfig,
+    ) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, incoming) = mpsc::channel(config.max_handshakes.max(1));
+        let (lifecycle, _) = watch::channel(Lifecycle::Running);
+        let connections = Arc::new(Semaphore::new(config.max_connections));
+        let max_connections = config.max_connections;
+
+        let task = tokio::spawn(accept_loop(
+            listener,
+            credentials,
+            config,
+            Arc::clone(&connections),
+            lifecycle.subscribe(),
+            sender,
+        ));
+
+        Ok(Self {
+            incoming: Mutex::new(incoming),
+            local_addr,
+            task: StdMutex::new(Some(task)),
+            connections,
+            max_connections,
+            lifecycle,
+        })
+    }
+
+    /// Returns the local address the listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Waits for the next connection that completed its handshake.
+    pub async fn accept(&self) -> Result<Connection, Error> {
+        let mut lifecycle = self.lifecycle.subscribe();
+
+        tokio::select! {
+            connection = async { self.incoming.lock().await.recv().await } => {
+                connection.ok_or(Error::SocketDied)
+            }
+            _ = lifecycle.wait_for(|current| *current != Lifecycle::Running) => {
+                Err(Error::Closed)
+            }
+        }
+    }
+
+    /// Stops accepting connections, closes the idle ones, and gives the
+    /// active ones `grace` to finish before terminating them.
+    pub async fn shutdown(&self, grace: Duration) -> ShutdownReport {
+        let task = self.task.lock().unwrap().take();
+        if let Some(task) = task {
+            task.abort();
+            // Wait for the accept loop to release the slot it holds for the
+            // next connection.
+            let _ = task.await;
+        }
+
+        let alive =
+            |connections: &Semaphore| self.max_connections - connections.available_permits();
+        let open = alive(&self.connections);
+
+        self.lifecycle.send_replace(Lifecycle::Draining);
+
+        // Connections that completed their handshake but were never accepted
+        // are dropped right away.
+        let mut incoming = self.incoming.lock().await;
+        incoming.close();
+        while incoming.try_recv().is_ok() {}
+        drop(incoming);
+
+        let permits = u32::try_from(self.max_connections).unwrap_or(u32::MAX);
+        let aborted =
+            match tokio::time::timeout(grace, self.connections.acquire_many(permits)).await {
+                Ok(_) => 0,
+                Err(_) => alive(&self.connections),
+            };
+
+        self.lifecycle.send_replace(Lifecycle::Terminated);
+
+        ShutdownReport {
+            drained: open.saturating_sub(aborted),
+            aborted,
+        }
+    }
+}
+
+impl Drop for PtlsListener {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    credentials: Credentials,
+    config: ListenerConfig,
+    connections: Arc<Semaphore>,
+    lifecycle: watch::Receiver<Lifecycle>,
+    sender: mpsc::Sender<Connection>,
+) {
+    let handshakes = Arc::new(Semaphore::new(config.max_handshakes));
+
+    loop {
+        // Both semaphores are only closed on drop, which never happens here.
+        let Ok(connection_permit) = Arc::clone(&connections).acquire_owned().await else {
+            return;
+        };
+        let Ok(handshake_permit) = Arc::clone(&handshakes).acquire_owned().await else {
+            return;
+        };
+
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // Typically running out of file descriptors, give the
+            // connections some time to close.
+            Err(_) => {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let delay = match config.policy.as_ref().map(|policy| policy.admit(peer_addr)) {
+            None | Some(Admission::Allow) => None,
+            Some(Admission::Deny) => continue,
+            Some(Admission::Delay(delay)) => Some(delay),
+        };
+
+        let mut tunnel = Ptls::new(stream.into_split(), &credentials);
+        tunnel.set_timeout(config.handshake_timeout);
+        if let Some(inspector) = &config.hello_inspector {
+            tunnel.set_hello_inspector(Arc::clone(inspector));
+        }
+        for (server_name, credentials) in &config.identities {
+            tunnel.add_identity(server_name.clone(), credentials);
+        }
+
+        let handshakes = Arc::clone(&handshakes);
+        let sender = sender.clone();
+        let lifecycle = lifecycle.clone();
+        tokio::spawn(async move {
+            // A delayed peer does not hold a handshake slot while waiting.
+            let handshake_permit = match delay {
+                None => handshake_permit,
+                Some(delay) => {
+                    drop(handshake_permit);
+                    tokio::time::sleep(delay).await;
+
+                    match handshakes.acquire_owned().await {
+                        Ok(handshake_permit) => handshake_permit,
+                        Err(_) => return,
+                    }
+                }
+            };
+
+            let handshake = tunnel.handshake().await;
+            drop(handshake_permit);
+
+            // Connections completing their handshake during a shutdown are
+            // dropped, freeing their slot.
+            if handshake.is_ok() && *lifecycle.borrow() == Lifecycle::Running {
+                let _ = sender
+                    .send(Connection {
+                        tunnel,
+                        peer_addr,
+                        lifecycle,
+                        _permit: connection_permit,
+                    })
+                    .await;
+            }
+        });
+    }
+}
@@ -17,7 +17,7 @@ impl Display for Error {
         match self {
             Self::UnsupportedVersion(version) => write!(f, "Unsupported version {version}"),
             Self::PayloadTooLong => {
-                f.write_str("A single payload can transmit up to 16 MiB - 15 bytes of data.")
+                f.write_str("Payload exceeds the maximum size for the key in use.")
             }
             Self::Io(error) => error.fmt(f),
             Self::Rsa(error) => error.fmt(f),
@@ -2,6 +2,7 @@ mod error;
 
 pub use error::Error;
 
+use crate::CryptoConfig;
 use rand::thread_rng;
 use rsa::{traits::PublicKeyParts, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -9,6 +10,17 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 pub enum PtlsPayloadType {
     PublicKey = 0,
     EncryptedTraffic = 1,
+    StreamChunk = 2,
+    ServerName = 3,
+    CloseNotify = 4,
+}
+
+impl PtlsPayloadType {
+    /// Whether payloads of this type are encrypted with the recipient's
+    /// public key. The others are sent in clear, before the keys are known.
+    pub fn is_encrypted(&self) -> bool {
+        !matches!(self, Self::ServerName)
+    }
 }
 
 impl TryFrom<u8> for PtlsPayloadType {
@@ -18,14 +30,16 @@ impl TryFrom<u8> for PtlsPayloadType {
         match value {
             0 => Ok(Self::PublicKey),
             1 => Ok(Self::EncryptedTraffic),
-            _ => Err(Self::Error::InvalidContentType)
+            2 => Ok(Self::StreamChunk),
+            3 => Ok(Self::ServerName),
+            4 => Ok(Self::CloseNotify),
+            _ => Err(Self::Error::InvalidContentType),
         }
     }
 }
 
-/// The pTLS payload transmitted over TCP or UDP. Maximum 16MiB - 15B of data
-/// could transmitted in single payload. A single payload can transmit up to
-/// 16 MiB of data.
+/// The pTLS payload transmitted over TCP or UDP. The amount of data a single
+/// payload can carry depends on the key in use, see [`max_payload_size`].
 pub struct PtlsPayload {
     /// Content type of the payload.
     pub content_type: PtlsPayloadType,
@@ -37,11 +51,66 @@ pub struct PtlsPayload {
     pub payload: Vec<u8>,
 }
 
-/// Calculates the maximum TCP payload length that can be carried by the
-/// encrypted tunnel.
-pub fn max_payload_size(block_size: u16) -> u16 {
-    let block_count = u16::MAX / block_size;
-    (block_size - 11) * block_count - 5
+/// Length of the payload header: content type, version and length.
+pub const HEADER_LENGTH: usize = 5;
+
+/// Largest record, header included, that can be transmitted.
+pub const MAX_RECORD_LENGTH: usize = u16::MAX as usize;
+
+/// Padding scheme applied to each encrypted block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    /// RSAES-PKCS1-v1_5
+    Pkcs1v15,
+}
+
+impl Padding {
+    /// Number of bytes the padding adds to every block.
+    pub fn overhead(self) -> usize {
+        match self {
+            Self::Pkcs1v15 => 11,
+        }
+    }
+
+    /// Number of plaintext bytes a single block of `key_size` bytes can
+    /// carry, zero if the key is too small for the padding.
+    pub fn block_payload(self, key_size: usize) -> usize {
+        key_size.saturating_sub(self.overhead())
+    }
+}
+
+/// Calculates the maximum payload length that can be carried by a single
+/// record encrypted with a `key_size` bytes long key, without the record
+/// exceeding `record_limit` bytes.
+pub fn max_payload_size(key_size: usize, padding: Padding, record_limit: usize) -> usize {
+    let block_payload = padding.block_payload(key_size);
+    if block_payload == 0 {
+        return 0;
+    }
+
+    let block_count = record_limit.saturating_sub(HEADER_LENGTH) / key_size;
+    (block_payload * block_count).min(u16::MAX as usize)
+}
+
+/// The cleartext header preceding every payload.
+pub struct PtlsPayloadHeader {
+    /// Content type of the payload.
+    pub content_type: PtlsPayloadType,
+    /// Reserved for future use.
+    pub version: u16,
+    /// Length of the payload
+    pub length: u16,
+}
+
+impl PtlsPayloadHeader {
+    /// Reads a payload header, leaving its body in the reader.
+    pub async fn read<R: AsyncReadExt + Unpin>(br: &mut R) -> Result<Self, Error> {
+        Ok(Self {
+            content_type: br.read_u8().await?.try_into()?,
+            version: br.read_u16().await?,
+            length: br.read_u16().await?,
+        })
+    }
 }
 
 impl PtlsPayload {
@@ -58,19 +127,48 @@ impl PtlsPayload {
     pub async fn collect_once<R: AsyncReadExt + Unpin>(
         br: &mut R,
         private_key: &RsaPrivateKey,
+        config: &CryptoConfig,
     ) -> Result<Self, Error> {
-        let content_type = br.read_u8().await?;
-        let _version = br.read_u16().await?;
-        let length = br.read_u16().await?;
+        let header = PtlsPayloadHeader::read(br).await?;
+        Self::collect_body(header, br, private_key, config).await
+    }
 
-        if length > max_payload_size(private_key.size() as u16) {
-            return Err(Error::PayloadTooLong);
+    /// Retrieves and decrypts the body of a payload whose header has already
+    /// been read.
+    pub async fn collect_body<R: AsyncReadExt + Unpin>(
+        header: PtlsPayloadHeader,
+        br: &mut R,
+        private_key: &RsaPrivateKey,
+        config: &CryptoConfig,
+    ) -> Result<Self, Error> {
+        let PtlsPayloadHeader {
+            content_type,
+            version,
+            length,
+        } = header;
+
+        if !content_type.is_encrypted() {
+            let mut payload = vec![0; length as usize];
+            br.read_exact(&mut payload).await?;
+
+            return Ok(Self {
+                version,
+                ..Self::new(payload, content_type)
+            });
         }
 
         let block_size = private_key.size();
-        let block_count = length.div_ceil((block_size - 11) as u16);
+        let padding = Padding::Pkcs1v15;
+
+        if length as usize > max_payload_size(block_size, padding, MAX_RECORD_LENGTH) {
+            return Err(Error::PayloadTooLong);
+        }
+
+        let block_payload = padding.block_payload(block_size);
+        let block_count = (length as usize).div_ceil(block_payload);
 
-        let mut payload = Vec::with_capacity((block_size - 11) * block_count as usize);
+        let mut payload = Vec::with_capacity(block_payload * block_count);
+        let mut failure = None;
 
         for _ in 0..block_count {
             let mut handle = br.take(block_size as u64);
@@ -78,19 +176,47 @@ impl PtlsPayload {
 
             handle.read_to_end(&mut encrypted).await?;
 
-            payload.append(&mut private_key.decrypt(Pkcs1v15Encrypt, &encrypted)?);
+            let decrypted = if config.rsa_blinding {
+                private_key.decrypt_blinded(&mut thread_rng(), Pkcs1v15Encrypt, &encrypted)
+            } else {
+                private_key.decrypt(Pkcs1v15Encrypt, &encrypted)
+            };
+
+            match decrypted {
+                Ok(mut block) => payload.append(&mut block),
+                // Keep consuming the remaining blocks so that the rejection
+                // takes the same time wherever the bad block is.
+                Err(e) if config.constant_time => {
+                    failure.get_or_insert(e);
+                }
+                Err(e) => return Err(e.into()),
+            }
         }
 
-        Ok(PtlsPayload::new(payload, content_type.try_into()?))
+        if let Some(e) = failure {
+            return Err(e.into());
+        }
+
+        Ok(Self {
+            version,
+            ..Self::new(payload, content_type)
+        })
     }
 
-    /// Writes the payload into the buffer.
+    /// Writes the payload into the buffer, encrypting it with `public_key`
+    /// unless its content type is sent in clear.
     pub async fn write<W: AsyncWriteExt + Unpin>(
         self,
         bw: &mut W,
         public_key: &RsaPublicKey,
     ) -> Result<(), Error> {
-        if self.length > max_payload_size(public_key.size() as u16) {
+        let padding = Padding::Pkcs1v15;
+        let encrypted = self.content_type.is_encrypted();
+
+        if encrypted
+            && self.length as usize
+                > max_payload_size(public_key.size(), padding, MAX_RECORD_LENGTH)
+        {
             return Err(Error::PayloadTooLong);
         }
 
@@ -98,8 +224,14 @@ impl PtlsPayload {
         bw.write_u16(self.version).await?;
         bw.write_u16(self.length).await?;
 
-        let block_size = public_key.size() - 11;
-        let block_count = self.length.div_ceil(block_size as u16) as usize;
+        if !encrypted {
+            bw.write_all(&self.payload).await?;
+            bw.flush().await?;
+            return Ok(());
+        }
+
+        let block_size = padding.block_payload(public_key.size());
+        let block_count = (self.length as usize).div_ceil(block_size);
 
         for i in 0..block_count {
             let encrypted = public_key.encrypt(
@@ -111,6 +243,7 @@ impl PtlsPayload {
             bw.write_all(&encrypted).await?;
         }
 
+        bw.flush().await?;
         Ok(())
     }
 }
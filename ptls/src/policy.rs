@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Decision taken by a [`HandshakePolicy`] before a handshake starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// Perform the handshake right away.
+    Allow,
+    /// Close the connection without performing the handshake.
+    Deny,
+    /// Perform the handshake after the given delay.
+    Delay(Duration),
+}
+
+/// Policy consulted with the peer address of every accepted connection,
+/// before any cryptographic work is done.
+///
+/// It is implemented for closures, so a ban list can be as simple as
+/// `|addr: SocketAddr| if banned(addr) { Admission::Deny } else { Admission::Allow }`.
+pub trait HandshakePolicy: Send + Sync {
+    /// Decides whether a handshake with `peer_addr` may start.
+    fn admit(&self, peer_addr: SocketAddr) -> Admission;
+}
+
+impl<F> HandshakePolicy for F
+where
+    F: Fn(SocketAddr) -> Admission + Send + Sync,
+{
+    fn admit(&self, peer_addr: SocketAddr) -> Admission {
+        self(peer_addr)
+    }
+}
+
+/// Number of addresses tracked before full buckets are forgotten.
+const MAX_TRACKED: usize = 4096;
+
+/// Per-IP token-bucket throttle. Each address may start `burst` handshakes
+/// at once, refilled at `rate` handshakes per second. Handshakes beyond
+/// that are denied.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl TokenBucket {
+    /// Creates a throttle refilling `rate` tokens per second, holding up to
+    /// `burst` tokens per address.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl HandshakePolicy for TokenBucket {
+    fn admit(&self, peer_addr: SocketAddr) -> Admission {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let refill = |(tokens, since): (f64, Instant)| {
+            (tokens + now.duration_since(since).as_secs_f64() * self.rate).min(self.burst)
+        };
+
+        if buckets.len() >= MAX_TRACKED {
+            buckets.retain(|_, bucket| refill(*bucket) < self.burst);
+        }
+
+        let bucket = buckets.entry(peer_addr.ip()).or_insert((self.burst, now));
+        let tokens = refill(*bucket);
+
+        if tokens >= 1.0 {
+            *bucket = (tokens - 1.0, now);
+            Admission::Allow
+        } else {
+            *bucket = (tokens, now);
+            Admission::Deny
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use super::{Connection, Error, PtlsListener};
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+
+type Handler = Arc<dyn Fn(Connection) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Dispatches the connections accepted by a [`PtlsListener`] to handlers
+/// selected by the server name the client requested, so a single port can
+/// front several services. The identity each name is served with is set in
+/// [`ListenerConfig::identities`](crate::ListenerConfig::identities).
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: HashMap<String, Handler>,
+    fallback: Option<Handler>,
+}
+
+impl Router {
+    /// Creates a router without any route.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes the connections requesting `server_name` to `handler`.
+    pub fn route<F, Fut>(mut self, server_name: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(Connection) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.routes
+            .insert(server_name.into(), Arc::new(move |c| Box::pin(handler(c))));
+        self
+    }
+
+    /// Routes the connections matching no route, including the ones that
+    /// did not request a server name, to `handler`. Without a fallback they
+    /// are closed.
+    pub fn fallback<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Connection) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.fallback = Some(Arc::new(move |c| Box::pin(handler(c))));
+        self
+    }
+
+    async fn dispatch(self: Arc<Self>, connection: Connection) {
+        let handler = connection
+            .server_name()
+            .and_then(|server_name| self.routes.get(server_name))
+            .or(self.fallback.as_ref());
+
+        match handler {
+            Some(handler) => handler(connection).await,
+            None => {
+                let _ = connection.close().await;
+            }
+        }
+    }
+}
+
+impl PtlsListener {
+    /// Accepts connections and spawns the handler `router` selects for each,
+    /// until the listener shuts down.
+    pub async fn serve(&self, router: Router) -> Result<(), Error> {
+        let router = Arc::new(router);
+
+        loop {
+            match self.accept().await {
+                Ok(connection) => {
+                    tokio::spawn(Arc::clone(&router).dispatch(connection));
+                }
+                Err(Error::Closed) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use super::{payload, Error, Ptls};
+use payload::{max_payload_size, Padding, PtlsPayloadType, MAX_RECORD_LENGTH};
+use rsa::traits::PublicKeyParts;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Length of the chunk header: 64-bit index and the final-chunk flag.
+const CHUNK_HEADER_LENGTH: usize = 9;
+
+impl<R, W> Ptls<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Streams everything `reader` yields to the peer, returning the number
+    /// of bytes sent.
+    ///
+    /// The data is split into chunks carrying their index and a final-chunk
+    /// flag inside the encrypted payload, so the receiving
+    /// [`receive_to_writer`](Self::receive_to_writer) detects dropped,
+    /// reordered, or truncated chunks.
+    pub async fn send_reader<Rd: AsyncRead + Unpin>(&self, reader: &mut Rd) -> Result<u64, Error> {
+        let chunk_size = self.chunk_size()?;
+        let mut chunk = vec![0; CHUNK_HEADER_LENGTH + chunk_size];
+        let mut index: u64 = 0;
+        let mut sent: u64 = 0;
+
+        loop {
+            let mut filled = CHUNK_HEADER_LENGTH;
+            let mut last = false;
+
+            while filled < chunk.len() {
+                match reader.read(&mut chunk[filled..]).await {
+                    Ok(0) => {
+                        last = true;
+                        break;
+                    }
+                    Ok(read) => filled += read,
+                    Err(e) => return Err(Error::Payload(e.into())),
+                }
+            }
+
+            chunk[..8].copy_from_slice(&index.to_be_bytes());
+            chunk[8] = last as u8;
+
+            self.send_inner(&chunk[..filled], PtlsPayloadType::StreamChunk)
+                .await?;
+
+            sent += (filled - CHUNK_HEADER_LENGTH) as u64;
+            index += 1;
+
+            if last {
+                return Ok(sent);
+            }
+        }
+    }
+
+    /// Receives a stream sent with [`send_reader`](Self::send_reader) and
+    /// writes it into `writer`, returning the number of bytes received.
+    pub async fn receive_to_writer<Wr: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut Wr,
+    ) -> Result<u64, Error> {
+        let mut expected: u64 = 0;
+        let mut received: u64 = 0;
+
+        loop {
+            let payload = self.receive_inner().await?;
+
+            if !matches!(payload.content_type, PtlsPayloadType::StreamChunk)
+                || payload.payload.len() < CHUNK_HEADER_LENGTH
+            {
+                return Err(self.fail(Error::Payload(payload::Error::InvalidContentType)));
+            }
+
+            let index = u64::from_be_bytes(payload.payload[..8].try_into().unwrap());
+            if index != expected {
+                return Err(self.fail(Error::ChunkOutOfOrder(index)));
+            }
+
+            let data = &payload.payload[CHUNK_HEADER_LENGTH..];
+            if let Err(e) = writer.write_all(data).await {
+                return Err(Error::Payload(e.into()));
+            }
+
+            received += data.len() as u64;
+            expected += 1;
+
+            if payload.payload[8] != 0 {
+                writer.flush().await.map_err(|e| Error::Payload(e.into()))?;
+                return Ok(received);
+            }
+        }
+    }
+
+    fn chunk_size(&self) -> Result<usize, Error> {
+        let public_key = self.public_key.as_ref().ok_or(Error::NotReady)?;
+        let max = max_payload_size(public_key.size(), Padding::Pkcs1v15, MAX_RECORD_LENGTH);
+
+        match max.checked_sub(CHUNK_HEADER_LENGTH) {
+            Some(chunk_size) if chunk_size > 0 => Ok(chunk_size),
+            _ => Err(Error::Payload(payload::Error::PayloadTooLong)),
+        }
+    }
+}
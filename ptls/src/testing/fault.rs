@@ -0,0 +1,264 @@
+use crate::payload::{PtlsPayloadType, HEADER_LENGTH};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+/// A fault injected by [`Faulty`].
+///
+/// Offsets count the bytes that went through the wrapper in one direction,
+/// record indices count the records written through it, handshake included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Flips the bits of `mask` in the byte at `offset`.
+    Corrupt { offset: u64, mask: u8 },
+    /// Ends the stream after the given number of bytes.
+    Truncate(u64),
+    /// Fails with a connection reset once the given number of bytes went
+    /// through.
+    Disconnect(u64),
+    /// Delays every read and write operation.
+    Delay(Duration),
+    /// Silently drops the record with the given index. Write side only.
+    DropRecord(usize),
+    /// Delivers the record with the given index twice. Write side only.
+    DuplicateRecord(usize),
+}
+
+/// A reader and/or writer injecting [`Fault`]s into the wrapped one.
+///
+/// Wrap the read or the write half of a tunnel to target a single direction.
+/// Record faults need the size of the RSA key the records are encrypted
+/// with, see [`Faulty::block_size`].
+pub struct Faulty<T> {
+    inner: T,
+    faults: Vec<Fault>,
+    read_offset: u64,
+    write_offset: u64,
+    delay: Option<Pin<Box<Sleep>>>,
+    block_size: Option<usize>,
+    pending: Vec<u8>,
+    outgoing: VecDeque<u8>,
+    records: usize,
+    truncated: bool,
+}
+
+impl<T> Faulty<T> {
+    /// Wraps `inner`, injecting `faults`.
+    pub fn new(inner: T, faults: impl IntoIterator<Item = Fault>) -> Self {
+        Self {
+            inner,
+            faults: faults.into_iter().collect(),
+            read_offset: 0,
+            write_offset: 0,
+            delay: None,
+            block_size: None,
+            pending: Vec::new(),
+            outgoing: VecDeque::new(),
+            records: 0,
+            truncated: false,
+        }
+    }
+
+    /// Sets the size in bytes of the key encrypting the written records,
+    /// enabling [`Fault::DropRecord`] and [`Fault::DuplicateRecord`].
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    /// Returns the wrapped reader or writer.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn poll_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let delay = self.faults.iter().find_map(|fault| match fault {
+            Fault::Delay(delay) => Some(*delay),
+            _ => None,
+        });
+
+        if let Some(delay) = delay {
+            let sleep = self
+                .delay
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(delay)));
+            ready!(sleep.as_mut().poll(cx));
+            self.delay = None;
+        }
+
+        Poll::Ready(())
+    }
+
+    /// Returns how many of `len` bytes at `offset` may go through, or the
+    /// error to fail with.
+    fn allowed(&self, offset: u64, len: usize) -> io::Result<usize> {
+        let mut allowed = len as u64;
+
+        for fault in &self.faults {
+            match *fault {
+                Fault::Disconnect(at) if offset >= at => {
+                    return Err(io::ErrorKind::ConnectionReset.into())
+                }
+                Fault::Disconnect(at) | Fault::Truncate(at) => {
+                    allowed = allowed.min(at.saturating_sub(offset))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(allowed as usize)
+    }
+
+    fn corrupt(&self, offset: u64, data: &mut [u8]) {
+        for fault in &self.faults {
+            if let Fault::Corrupt { offset: at, mask } = *fault {
+                if at >= offset && at < offset + data.len() as u64 {
+                    data[(at - offset) as usize] ^= mask;
+                }
+            }
+        }
+    }
+
+    /// Moves the complete records out of `pending`, dropping and
+    /// duplicating them as configured.
+    fn split_records(&mut self) {
+        let Some(block_size) = self.block_size else {
+            self.outgoing.extend(self.pending.drain(..));
+            return;
+        };
+
+        while self.pending.len() >= HEADER_LENGTH {
+            let length = u16::from_be_bytes([self.pending[3], self.pending[4]]) as usize;
+            let body = match PtlsPayloadType::try_from(self.pending[0]) {
+                Ok(content_type) if !content_type.is_encrypted() => length,
+                _ => length.div_ceil(block_size.saturating_sub(11).max(1)) * block_size,
+            };
+
+            if self.pending.len() < HEADER_LENGTH + body {
+                return;
+            }
+
+            let record: Vec<u8> = self.pending.drain(..HEADER_LENGTH + body).collect();
+            let index = self.records;
+            self.records += 1;
+
+            if self.faults.contains(&Fault::DropRecord(index)) {
+                continue;
+            }
+            if self.faults.contains(&Fault::DuplicateRecord(index)) {
+                self.outgoing.extend(&record);
+            }
+            self.outgoing.extend(record);
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Faulty<T> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.outgoing.is_empty() {
+            if self.truncated {
+                self.outgoing.clear();
+                break;
+            }
+
+            let allowed = self.allowed(self.write_offset, self.outgoing.len())?;
+            if allowed == 0 {
+                self.truncated = true;
+                ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+                continue;
+            }
+
+            let (front, _) = self.outgoing.as_slices();
+            let mut chunk = front[..front.len().min(allowed)].to_vec();
+            self.corrupt(self.write_offset, &mut chunk);
+
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &chunk))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+
+            self.outgoing.drain(..written);
+            self.write_offset += written as u64;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Faulty<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_delay(cx));
+
+        let allowed = this.allowed(this.read_offset, buf.remaining())?;
+        if allowed == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut data = vec![0; allowed];
+        let mut limited = ReadBuf::new(&mut data);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+
+        let read = limited.filled().len();
+        this.corrupt(this.read_offset, &mut data[..read]);
+        buf.put_slice(&data[..read]);
+        this.read_offset += read as u64;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Faulty<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_delay(cx));
+        ready!(this.poll_drain(cx))?;
+
+        this.pending.extend_from_slice(buf);
+        this.split_records();
+
+        // Complete records are forwarded right away, what the inner writer
+        // cannot take yet goes out on the next write or flush.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+
+        if this.truncated {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+
+        if this.truncated {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,100 @@
+//! Helpers for testing code built on pTLS without sockets.
+//!
+//! Available with the `testing` feature.
+
+mod fault;
+
+pub use fault::{Fault, Faulty};
+
+use super::{Error, Ptls};
+use rand::thread_rng;
+use rsa::{traits::PublicKeyParts, RsaPrivateKey, RsaPublicKey};
+use tokio::io::{simplex, AsyncWrite, ReadHalf, SimplexStream, WriteHalf};
+
+/// A pTLS tunnel over an in-memory pipe.
+pub type MemoryPtls = Ptls<ReadHalf<SimplexStream>, WriteHalf<SimplexStream>>;
+
+/// Settings of the tunnels created by [`memory_pair`].
+#[derive(Debug, Clone)]
+pub struct PairConfig {
+    /// Size of the generated keys. The default is small to keep tests fast
+    /// and must not be taken as a recommendation.
+    pub key_bits: usize,
+    /// Capacity of each direction of the in-memory pipe.
+    pub buffer_size: usize,
+    /// Whether the key exchange is performed before the pair is returned.
+    pub handshake: bool,
+}
+
+impl Default for PairConfig {
+    fn default() -> Self {
+        Self {
+            key_bits: 512,
+            buffer_size: u16::MAX as usize,
+            handshake: true,
+        }
+    }
+}
+
+/// Creates a connected `(client, server)` tunnel pair with fresh keys.
+///
+/// The client already knows the server's public key. Unless
+/// [`PairConfig::handshake`] is set, the key exchange is left to the caller:
+/// `client.send_public_key()` and `server.handshake()`.
+pub async fn memory_pair(config: PairConfig) -> Result<(MemoryPtls, MemoryPtls), Error> {
+    pair_with(config, |write, _| write).await
+}
+
+/// Creates a tunnel pair like [`memory_pair`] whose client to server
+/// direction goes through a [`Faulty`] writer injecting `faults`.
+///
+/// The faults also apply to the key exchange when
+/// [`PairConfig::handshake`] is set, in which case its failure is returned.
+pub async fn faulty_pair(
+    config: PairConfig,
+    faults: impl IntoIterator<Item = Fault>,
+) -> Result<
+    (
+        Ptls<ReadHalf<SimplexStream>, Faulty<WriteHalf<SimplexStream>>>,
+        MemoryPtls,
+    ),
+    Error,
+> {
+    pair_with(config, |write, block_size| {
+        Faulty::new(write, faults).block_size(block_size)
+    })
+    .await
+}
+
+/// Creates a tunnel pair, wrapping the client's writer with `wrap`, which
+/// is also given the size of the server key.
+async fn pair_with<W: AsyncWrite + Unpin>(
+    config: PairConfig,
+    wrap: impl FnOnce(WriteHalf<SimplexStream>, usize) -> W,
+) -> Result<(Ptls<ReadHalf<SimplexStream>, W>, MemoryPtls), Error> {
+    let mut rng = thread_rng();
+
+    let server_private = RsaPrivateKey::new(&mut rng, config.key_bits)?;
+    let server_public = RsaPublicKey::from(&server_private);
+    let client_private = RsaPrivateKey::new(&mut rng, config.key_bits)?;
+
+    let (server_read, client_write) = simplex(config.buffer_size);
+    let (client_read, server_write) = simplex(config.buffer_size);
+
+    let mut server = Ptls::new((server_read, server_write), server_private);
+    let client_write = wrap(client_write, server_public.size());
+    let mut client = Ptls::new((client_read, client_write), client_private);
+
+    client.set_public_key(server_public);
+
+    if config.handshake {
+        let (sent, received) = tokio::join! {
+            client.send_public_key(),
+            server.handshake(),
+        };
+        sent?;
+        received?;
+    }
+
+    Ok((client, server))
+}
@@ -1,9 +1,344 @@
 use super::*;
-use payload::max_payload_size;
+use payload::{max_payload_size, Padding, HEADER_LENGTH, MAX_RECORD_LENGTH};
+use rsa::traits::PrivateKeyParts;
+use testing::{memory_pair, PairConfig};
 use tokio::io::simplex;
 
 #[tokio::test]
 async fn mtls_max_buffer() {
+    let (client, server) = memory_pair(PairConfig::default()).await.unwrap();
+
+    let data = vec![1; max_payload_size(64, Padding::Pkcs1v15, MAX_RECORD_LENGTH)];
+
+    client.send(&data).await.unwrap();
+    assert_eq!(data, server.receive().await.unwrap());
+}
+
+#[tokio::test]
+async fn multi_prime_server_key() {
+    use rand::thread_rng;
+
+    let server_private = keys::generate_multi_prime(1024, 3).unwrap();
+    keys::validate(&server_private).unwrap();
+    assert_eq!(server_private.primes().len(), 3);
+
+    let server_public = RsaPublicKey::from(&server_private);
+    let client_private = RsaPrivateKey::new(&mut thread_rng(), 512).unwrap();
+
+    let (mock_server_read, mock_client_write) = simplex(u16::MAX as usize);
+    let (mock_client_read, mock_server_write) = simplex(u16::MAX as usize);
+
+    let mut mock_server_ptls = Ptls::new((mock_server_read, mock_server_write), server_private);
+    let mut mock_client_ptls = Ptls::new((mock_client_read, mock_client_write), client_private);
+
+    mock_client_ptls.set_public_key(server_public);
+    let (client_send, server_handshake) = tokio::join! {
+        mock_client_ptls.send_public_key(),
+        mock_server_ptls.handshake(),
+    };
+    client_send.unwrap();
+    server_handshake.unwrap();
+
+    mock_client_ptls.send(b"multi-prime").await.unwrap();
+    assert_eq!(
+        b"multi-prime".to_vec(),
+        mock_server_ptls.receive().await.unwrap()
+    );
+
+    assert!(matches!(
+        keys::generate_multi_prime(1024, 4),
+        Err(Error::PrimeCount(4))
+    ));
+}
+
+#[test]
+fn max_payload_size_large_keys() {
+    for bits in [1024, 2048, 3072, 4096, 8192] {
+        let key_size = bits / 8;
+        let padding = Padding::Pkcs1v15;
+        let max = max_payload_size(key_size, padding, MAX_RECORD_LENGTH);
+
+        assert!(max > 0 && max <= u16::MAX as usize);
+
+        let block_count = max.div_ceil(padding.block_payload(key_size));
+        assert!(HEADER_LENGTH + block_count * key_size <= MAX_RECORD_LENGTH);
+    }
+
+    assert_eq!(
+        max_payload_size(11, Padding::Pkcs1v15, MAX_RECORD_LENGTH),
+        0
+    );
+}
+
+#[tokio::test]
+async fn stream_chunks() {
+    use rand::{thread_rng, RngCore};
+
+    let (client, server) = memory_pair(PairConfig::default()).await.unwrap();
+
+    let mut data = vec![0; 100_000];
+    thread_rng().fill_bytes(&mut data);
+
+    let mut reader = data.as_slice();
+    let mut received = Vec::new();
+    let (sent, collected) = tokio::join! {
+        client.send_reader(&mut reader),
+        server.receive_to_writer(&mut received),
+    };
+
+    assert_eq!(sent.unwrap(), data.len() as u64);
+    assert_eq!(collected.unwrap(), data.len() as u64);
+    assert_eq!(data, received);
+}
+
+#[tokio::test]
+async fn server_identity_selection() {
+    use rand::thread_rng;
+
+    let mut rng = thread_rng();
+
+    let default_private = RsaPrivateKey::new(&mut rng, 512).unwrap();
+    let tenant_private = RsaPrivateKey::new(&mut rng, 512).unwrap();
+    let tenant_public = RsaPublicKey::from(&tenant_private);
+    let client_private = RsaPrivateKey::new(&mut rng, 512).unwrap();
+
+    let (mock_server_read, mock_client_write) = simplex(u16::MAX as usize);
+    let (mock_client_read, mock_server_write) = simplex(u16::MAX as usize);
+
+    let mut mock_server_ptls = Ptls::new((mock_server_read, mock_server_write), default_private);
+    let mut mock_client_ptls = Ptls::new((mock_client_read, mock_client_write), client_private);
+
+    mock_server_ptls.add_identity("tenant", tenant_private);
+    mock_client_ptls.set_server_name("tenant");
+    mock_client_ptls.set_public_key(tenant_public);
+
+    let (client_send, server_handshake) = tokio::join! {
+        mock_client_ptls.send_public_key(),
+        mock_server_ptls.handshake(),
+    };
+    client_send.unwrap();
+    server_handshake.unwrap();
+
+    assert_eq!(mock_server_ptls.server_name(), Some("tenant"));
+
+    mock_client_ptls.send(b"tenant data").await.unwrap();
+    assert_eq!(
+        b"tenant data".to_vec(),
+        mock_server_ptls.receive().await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn credential_rotation() {
+    use rand::thread_rng;
+
+    let mut rng = thread_rng();
+
+    let old_private = RsaPrivateKey::new(&mut rng, 512).unwrap();
+    let old_public = RsaPublicKey::from(&old_private);
+    let new_private = RsaPrivateKey::new(&mut rng, 512).unwrap();
+    let new_public = RsaPublicKey::from(&new_private);
+
+    let credentials = Credentials::new(old_private);
+
+    let (old_server_read, old_client_write) = simplex(u16::MAX as usize);
+    let (old_client_read, old_server_write) = simplex(u16::MAX as usize);
+    let mut old_server = Ptls::new((old_server_read, old_server_write), &credentials);
+    let mut old_client = Ptls::new(
+        (old_client_read, old_client_write),
+        RsaPrivateKey::new(&mut rng, 512).unwrap(),
+    );
+    old_client.set_public_key(old_public);
+
+    let previous = credentials.swap_private_key(new_private);
+    assert_eq!(
+        RsaPublicKey::from(&*previous),
+        RsaPublicKey::from(&*old_server.private_key)
+    );
+
+    let (new_server_read, new_client_write) = simplex(u16::MAX as usize);
+    let (new_client_read, new_server_write) = simplex(u16::MAX as usize);
+    let mut new_server = Ptls::new((new_server_read, new_server_write), &credentials);
+    let mut new_client = Ptls::new(
+        (new_client_read, new_client_write),
+        RsaPrivateKey::new(&mut rng, 512).unwrap(),
+    );
+    new_client.set_public_key(new_public);
+
+    let (old_send, old_handshake, new_send, new_handshake) = tokio::join! {
+        old_client.send_public_key(),
+        old_server.handshake(),
+        new_client.send_public_key(),
+        new_server.handshake(),
+    };
+    old_send.unwrap();
+    old_handshake.unwrap();
+    new_send.unwrap();
+    new_handshake.unwrap();
+
+    old_client.send(b"old").await.unwrap();
+    new_client.send(b"new").await.unwrap();
+    assert_eq!(b"old".to_vec(), old_server.receive().await.unwrap());
+    assert_eq!(b"new".to_vec(), new_server.receive().await.unwrap());
+}
+
+#[tokio::test]
+async fn credential_renewal() {
+    use rand::thread_rng;
+    use std::time::{Duration, SystemTime};
+
+    let mut rng = thread_rng();
+
+    let old_private = RsaPrivateKey::new(&mut rng, 512).unwrap();
+    let new_private = RsaPrivateKey::new(&mut rng, 512).unwrap();
+    let new_public = RsaPublicKey::from(&new_private);
+
+    let credentials =
+        Credentials::new(old_private).expiring_at(SystemTime::now() + Duration::from_millis(300));
+
+    let mut renewed = Some(new_private);
+    let (events, mut received_events) = tokio::sync::mpsc::unbounded_channel();
+    let renewal = credentials.spawn_renewal(
+        Duration::from_millis(200),
+        move || {
+            let renewed = renewed.take();
+            async move {
+                let expires_at = SystemTime::now() + Duration::from_secs(3600);
+                renewed.map(|key| (key, expires_at)).ok_or(Error::NotReady)
+            }
+        },
+        move |event| events.send(event).unwrap(),
+    );
+
+    assert!(matches!(
+        received_events.recv().await,
+        Some(RenewalEvent::Started(_))
+    ));
+    assert!(matches!(
+        received_events.recv().await,
+        Some(RenewalEvent::Renewed(_))
+    ));
+    assert_eq!(RsaPublicKey::from(&*credentials.load()), new_public);
+
+    renewal.abort();
+}
+
+#[tokio::test]
+async fn listener_connection_limit() {
+    use rand::thread_rng;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    let mut rng = thread_rng();
+
+    let server_private = RsaPrivateKey::new(&mut rng, 512).unwrap();
+    let server_public = RsaPublicKey::from(&server_private);
+
+    let listener = PtlsListener::bind(
+        "127.0.0.1:0",
+        Credentials::new(server_private),
+        ListenerConfig {
+            max_handshakes: 1,
+            max_connections: 1,
+            handshake_timeout: None,
+            policy: None,
+            hello_inspector: None,
+            identities: Default::default(),
+        },
+    )
+    .await
+    .unwrap();
+
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        let stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+        let mut client = Ptls::new(
+            stream.into_split(),
+            RsaPrivateKey::new(&mut rng, 512).unwrap(),
+        );
+        client.set_public_key(server_public.clone());
+        client.send_public_key().await.unwrap();
+        clients.push(client);
+    }
+
+    let first = listener.accept().await.unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), listener.accept())
+            .await
+            .is_err()
+    );
+
+    drop(first);
+    listener.accept().await.unwrap();
+}
+
+#[tokio::test]
+async fn listener_graceful_shutdown() {
+    use rand::thread_rng;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    let mut rng = thread_rng();
+
+    let server_private = RsaPrivateKey::new(&mut rng, 512).unwrap();
+    let server_public = RsaPublicKey::from(&server_private);
+
+    let listener = PtlsListener::bind(
+        "127.0.0.1:0",
+        Credentials::new(server_private),
+        ListenerConfig::default(),
+    )
+    .await
+    .unwrap();
+
+    let mut clients = Vec::new();
+    let mut connections = Vec::new();
+    for _ in 0..2 {
+        let stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+        let mut client = Ptls::new(
+            stream.into_split(),
+            RsaPrivateKey::new(&mut rng, 512).unwrap(),
+        );
+        client.set_public_key(server_public.clone());
+        client.send_public_key().await.unwrap();
+        clients.push(client);
+        connections.push(listener.accept().await.unwrap());
+    }
+
+    // An idle connection, waiting for data when the shutdown begins.
+    let idle = connections.pop().unwrap();
+    let idle = tokio::spawn(async move { idle.receive().await });
+
+    // An active connection, busy for longer than the grace period.
+    let active = connections.pop().unwrap();
+
+    let report = listener.shutdown(Duration::from_millis(300)).await;
+    assert_eq!(
+        report,
+        ShutdownReport {
+            drained: 1,
+            aborted: 1
+        }
+    );
+
+    assert!(matches!(idle.await.unwrap(), Err(Error::Closed)));
+    assert!(matches!(active.send(b"late").await, Err(Error::SocketDied)));
+}
+
+#[test]
+fn token_bucket_throttle() {
+    let throttle = TokenBucket::new(0.0, 2);
+    let peer = "192.0.2.1:4000".parse().unwrap();
+    let other = "192.0.2.2:4000".parse().unwrap();
+
+    assert_eq!(throttle.admit(peer), Admission::Allow);
+    assert_eq!(throttle.admit(peer), Admission::Allow);
+    assert_eq!(throttle.admit(peer), Admission::Deny);
+    assert_eq!(throttle.admit(other), Admission::Allow);
+}
+
+#[tokio::test]
+async fn hello_inspector_rejects() {
     use rand::thread_rng;
 
     let mut rng = thread_rng();
@@ -18,16 +353,115 @@ async fn mtls_max_buffer() {
     let mut mock_server_ptls = Ptls::new((mock_server_read, mock_server_write), server_private);
     let mut mock_client_ptls = Ptls::new((mock_client_read, mock_client_write), client_private);
 
+    mock_server_ptls.set_hello_inspector(Arc::new(|hello: &ClientHello| {
+        match hello.server_name.as_deref() {
+            Some("blocked") => HelloDecision::Reject,
+            _ => HelloDecision::Accept,
+        }
+    }));
+    mock_client_ptls.set_server_name("blocked");
     mock_client_ptls.set_public_key(server_public);
+
     let (client_send, server_handshake) = tokio::join! {
         mock_client_ptls.send_public_key(),
         mock_server_ptls.handshake(),
     };
     client_send.unwrap();
-    server_handshake.unwrap();
+    assert!(matches!(server_handshake, Err(Error::Rejected)));
+}
+
+#[tokio::test]
+async fn listener_routes_by_server_name() {
+    use rand::thread_rng;
+    use tokio::net::TcpStream;
+
+    let mut rng = thread_rng();
+
+    let default_private = RsaPrivateKey::new(&mut rng, 512).unwrap();
+    let api_private = RsaPrivateKey::new(&mut rng, 512).unwrap();
+    let api_public = RsaPublicKey::from(&api_private);
+
+    let mut config = ListenerConfig::default();
+    config
+        .identities
+        .insert("api".to_string(), Credentials::new(api_private));
+
+    let listener = Arc::new(
+        PtlsListener::bind("127.0.0.1:0", Credentials::new(default_private), config)
+            .await
+            .unwrap(),
+    );
+
+    let router = Router::new()
+        .route("api", |connection: Connection| async move {
+            connection.send(b"api").await.unwrap();
+        })
+        .fallback(|connection: Connection| async move {
+            connection.send(b"fallback").await.unwrap();
+        });
+    tokio::spawn({
+        let listener = Arc::clone(&listener);
+        async move { listener.serve(router).await }
+    });
+
+    let stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+    let mut client = Ptls::new(
+        stream.into_split(),
+        RsaPrivateKey::new(&mut rng, 512).unwrap(),
+    );
+    client.set_server_name("api");
+    client.set_public_key(api_public);
+    client.send_public_key().await.unwrap();
+
+    assert_eq!(client.receive().await.unwrap(), b"api".to_vec());
+}
+
+#[tokio::test]
+async fn fault_injection() {
+    use testing::{faulty_pair, Fault};
+
+    // The handshake is record 0, the first message record 1.
+    let (client, server) = faulty_pair(
+        PairConfig::default(),
+        [Fault::DuplicateRecord(1), Fault::DropRecord(2)],
+    )
+    .await
+    .unwrap();
+
+    for message in ["one", "two", "three"] {
+        client.send(message.as_bytes()).await.unwrap();
+    }
+    assert_eq!(server.receive().await.unwrap(), b"one".to_vec());
+    assert_eq!(server.receive().await.unwrap(), b"one".to_vec());
+    assert_eq!(server.receive().await.unwrap(), b"three".to_vec());
+
+    // A 512-bit key encrypts the client's public key in two blocks.
+    let handshake_length = (HEADER_LENGTH + 2 * 64) as u64;
+
+    let (client, server) = faulty_pair(
+        PairConfig::default(),
+        [Fault::Corrupt {
+            offset: handshake_length + HEADER_LENGTH as u64,
+            mask: 0xff,
+        }],
+    )
+    .await
+    .unwrap();
+
+    client.send(b"corrupted").await.unwrap();
+    assert!(server.receive().await.is_err());
+    assert!(matches!(server.get_state(), PtlsState::TransmitError));
 
-    let data = vec![1; max_payload_size(64) as usize];
+    let (client, server) = faulty_pair(
+        PairConfig::default(),
+        [Fault::Truncate(handshake_length + 3)],
+    )
+    .await
+    .unwrap();
 
-    mock_client_ptls.send(&data).await.unwrap();
-    assert_eq!(data, mock_server_ptls.receive().await.unwrap());
+    client.send(b"truncated").await.unwrap();
+    assert!(matches!(
+        server.receive().await,
+        Err(Error::Payload(payload::Error::Io(_)))
+    ));
 }